@@ -0,0 +1,234 @@
+//! Intel ABI implementation.
+//!
+//! This module assigns argument and return-value locations for the calling
+//! conventions supported by the Intel backend, and reports which registers
+//! each convention treats as callee-saved.
+//!
+//! Two conventions are supported today:
+//!
+//! - `CallConv::SystemV`: the System V AMD64 ABI used on Linux, macOS and
+//!   the BSDs.
+//! - `CallConv::WindowsFastcall`: the Windows x64 "fastcall" ABI used by
+//!   MSVC and the Windows API.
+
+use ir::{self, ArgumentLoc, ArgumentPurpose, CallConv, Signature, Type};
+use isa::{RegClass, RegUnit};
+use regalloc::AllocatableSet;
+use settings as shared_settings;
+use super::registers::{FPR, GPR, RU};
+
+/// Size in bytes of the "home"/shadow space that the Windows x64 fastcall
+/// convention requires the caller to reserve for the first four arguments,
+/// even when those arguments are passed in registers. The callee is free to
+/// spill its register arguments into this space.
+pub const FASTCALL_SHADOW_SPACE: i64 = 32;
+
+/// Legalize the argument and return value list in `sig` for its calling
+/// convention, assigning each value a register or an incoming/outgoing
+/// stack slot.
+pub fn legalize_signature(sig: &mut Signature, flags: &shared_settings::Flags, current: bool) {
+    match sig.call_conv {
+        CallConv::WindowsFastcall => legalize_fastcall(sig, flags, current),
+        _ => legalize_system_v(sig, flags, current),
+    }
+}
+
+fn legalize_system_v(sig: &mut Signature, _flags: &shared_settings::Flags, _current: bool) {
+    const INT_ARG_GPRS: [RU; 6] = [RU::rdi, RU::rsi, RU::rdx, RU::rcx, RU::r8, RU::r9];
+    const INT_RET_GPRS: [RU; 2] = [RU::rax, RU::rdx];
+
+    let mut next_gpr = 0;
+    let mut next_fpr = 0;
+    let mut next_stack: i64 = 0;
+
+    for arg in sig.params.iter_mut() {
+        if arg.purpose != ArgumentPurpose::Normal {
+            continue;
+        }
+        if arg.value_type.is_float() {
+            if next_fpr < 8 {
+                arg.location = ArgumentLoc::Reg(fpr_unit(next_fpr));
+                next_fpr += 1;
+                continue;
+            }
+        } else if next_gpr < INT_ARG_GPRS.len() {
+            arg.location = ArgumentLoc::Reg(INT_ARG_GPRS[next_gpr] as RegUnit);
+            next_gpr += 1;
+            continue;
+        }
+        arg.location = ArgumentLoc::Stack(next_stack as i32);
+        next_stack += 8;
+    }
+
+    let mut next_ret_gpr = 0;
+    let mut next_ret_fpr = 0;
+    for ret in sig.returns.iter_mut() {
+        if ret.purpose != ArgumentPurpose::Normal {
+            continue;
+        }
+        if ret.value_type.is_float() {
+            ret.location = ArgumentLoc::Reg(fpr_unit(next_ret_fpr));
+            next_ret_fpr += 1;
+        } else if next_ret_gpr < INT_RET_GPRS.len() {
+            ret.location = ArgumentLoc::Reg(INT_RET_GPRS[next_ret_gpr] as RegUnit);
+            next_ret_gpr += 1;
+        }
+    }
+}
+
+/// Assign registers and stack slots for the Windows x64 fastcall
+/// convention. Unlike System V, the integer and floating-point argument
+/// cursors advance together: the `N`-th assigned argument always occupies
+/// "slot" `N`, whether that slot happens to be a GPR or an XMM register, so
+/// that the shadow space laid out by the caller lines up with the register
+/// set the callee expects to spill. `next_slot` only advances for arguments
+/// actually assigned a location here -- a leading special-purpose argument
+/// (skipped below) must not shift every later argument's register, the way
+/// keying off `enumerate()`'s positional index would.
+fn legalize_fastcall(sig: &mut Signature, _flags: &shared_settings::Flags, _current: bool) {
+    const INT_ARG_GPRS: [RU; 4] = [RU::rcx, RU::rdx, RU::r8, RU::r9];
+
+    let mut next_slot = 0;
+    let mut next_stack: i64 = FASTCALL_SHADOW_SPACE;
+
+    for arg in sig.params.iter_mut() {
+        if arg.purpose != ArgumentPurpose::Normal {
+            continue;
+        }
+        if next_slot < INT_ARG_GPRS.len() {
+            arg.location = if arg.value_type.is_float() {
+                ArgumentLoc::Reg(fpr_unit(next_slot))
+            } else {
+                ArgumentLoc::Reg(INT_ARG_GPRS[next_slot] as RegUnit)
+            };
+            next_slot += 1;
+        } else {
+            arg.location = ArgumentLoc::Stack(next_stack as i32);
+            next_stack += 8;
+        }
+    }
+
+    for ret in sig.returns.iter_mut() {
+        if ret.purpose != ArgumentPurpose::Normal {
+            continue;
+        }
+        ret.location = if ret.value_type.is_float() {
+            ArgumentLoc::Reg(fpr_unit(0))
+        } else {
+            ArgumentLoc::Reg(RU::rax as RegUnit)
+        };
+    }
+}
+
+fn fpr_unit(index: usize) -> RegUnit {
+    (RU::xmm0 as RegUnit) + index as RegUnit
+}
+
+/// Get the register class to use for an ABI argument or return value of
+/// type `ty`.
+pub fn regclass_for_abi_type(ty: Type) -> RegClass {
+    if ty.is_float() { FPR } else { GPR }
+}
+
+/// Get the set of registers that this calling convention requires the
+/// callee to preserve across the call, in the order they should be
+/// pushed in the prologue (and popped, in reverse, in the epilogue).
+///
+/// Normally the frame pointer (`rbp`) is handled separately by
+/// `prologue_epilogue` and is not included here. When `omit_frame_pointer`
+/// is set, though, `rbp` is just another allocatable GPR as far as the
+/// prologue is concerned, so it must still be saved and restored like any
+/// other callee-saved register -- it's appended to the end of the list in
+/// that case.
+pub fn callee_saved_registers(call_conv: CallConv, omit_frame_pointer: bool) -> Vec<RU> {
+    let mut csrs = match call_conv {
+        CallConv::WindowsFastcall => FASTCALL_CALLEE_SAVED.to_vec(),
+        _ => SYSTEM_V_CALLEE_SAVED.to_vec(),
+    };
+    if omit_frame_pointer {
+        csrs.push(RU::rbp);
+    }
+    csrs
+}
+
+/// Get the set of callee-saved XMM registers for `call_conv`, empty unless
+/// the convention treats any of them as callee-saved. Only the Windows
+/// fastcall convention does: `xmm6`-`xmm15`.
+pub fn callee_saved_fprs(call_conv: CallConv) -> &'static [RU] {
+    match call_conv {
+        CallConv::WindowsFastcall => &FASTCALL_CALLEE_SAVED_XMM,
+        _ => &[],
+    }
+}
+
+const SYSTEM_V_CALLEE_SAVED: [RU; 5] = [RU::rbx, RU::r12, RU::r13, RU::r14, RU::r15];
+
+const FASTCALL_CALLEE_SAVED: [RU; 7] = [
+    RU::rbx,
+    RU::rdi,
+    RU::rsi,
+    RU::r12,
+    RU::r13,
+    RU::r14,
+    RU::r15,
+];
+const FASTCALL_CALLEE_SAVED_XMM: [RU; 10] = [
+    RU::xmm6,
+    RU::xmm7,
+    RU::xmm8,
+    RU::xmm9,
+    RU::xmm10,
+    RU::xmm11,
+    RU::xmm12,
+    RU::xmm13,
+    RU::xmm14,
+    RU::xmm15,
+];
+
+/// Get the set of registers which can be used by the register allocator
+/// for `func`.
+///
+/// `rbp` is reserved for use as the frame pointer unless `isa_flags` asks
+/// for it to be omitted, in which case `rbp` is freed up for general use.
+pub fn allocatable_registers(
+    func: &ir::Function,
+    _flags: &shared_settings::Flags,
+    isa_flags: &super::settings::Flags,
+) -> AllocatableSet {
+    let mut regs = AllocatableSet::new();
+    regs.remove(RU::rsp as RegUnit);
+    if !isa_flags.omit_frame_pointer() {
+        regs.remove(RU::rbp as RegUnit);
+    }
+    regs
+}
+
+/// Name of the external routine that performs stack probing for frames too
+/// large to unroll inline. Implemented by the runtime/embedder and expected
+/// to touch every page between the old and new stack pointer, taking the
+/// requested frame size in `rax` and leaving the stack pointer unchanged.
+const PROBESTACK_NAME: &'static str = "__cranelift_probestack";
+
+/// Register used to pass the frame size to `__cranelift_probestack`.
+pub const PROBESTACK_ARG_REG: RU = RU::rax;
+
+/// Declare the `__cranelift_probestack` routine in `func`, returning a
+/// reference to it that can be used as the callee of a `call` instruction.
+/// Each call imports a new signature and function; an existing declaration
+/// is not reused.
+pub fn get_probestack_funcref(func: &mut ir::Function, call_conv: CallConv) -> ir::FuncRef {
+    let sig = func.import_signature(ir::Signature {
+        call_conv,
+        params: vec![ir::AbiParam::special_reg(
+            ir::types::I64,
+            ArgumentPurpose::Normal,
+            PROBESTACK_ARG_REG as RegUnit,
+        )],
+        returns: vec![],
+    });
+
+    func.import_function(ir::ExtFuncData {
+        name: ir::ExternalName::testcase(PROBESTACK_NAME),
+        signature: sig,
+    })
+}
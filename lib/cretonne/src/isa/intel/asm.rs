@@ -0,0 +1,118 @@
+//! Lowering support for the `InlineAsm` instruction.
+//!
+//! `InlineAsm` lets an embedding compiler (for example a Rust codegen
+//! frontend) splice hand-written machine code into a function, with
+//! operands constrained to a register class -- or, for explicit-register
+//! constraints, to one specific `RegUnit`. Register allocation pins those
+//! operands down like any other constrained use, and clobbers are treated
+//! as call-clobbered across the instruction.
+//!
+//! The instruction's template is already-assembled machine code with one
+//! placeholder byte per operand (its ModRM reg-field bits aren't known
+//! until the operand's register has been allocated). Emitting it is just a
+//! matter of patching those placeholder bytes with the real register
+//! encodings and copying the rest verbatim -- there's no fixed opcode or
+//! encoding recipe bit pattern the way there is for every other
+//! instruction this backend emits.
+
+use super::registers::{FPR8, GPR8, MASK};
+use binemit::CodeSink;
+use ir::{Function, Inst, InstructionData};
+use isa::{RegClass, RegUnit};
+use regalloc::RegDiversions;
+
+/// The register class an `InlineAsm` operand was constrained to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AsmRegClass {
+    /// A general-purpose register, restricted to `rax`-`rdi` -- see
+    /// `to_reg_class`.
+    Gpr,
+    /// A floating-point / vector register, restricted to `xmm0`-`xmm7` --
+    /// see `to_reg_class`.
+    Fpr,
+    /// An AVX-512 mask ("k") register.
+    Mask,
+}
+
+impl AsmRegClass {
+    /// The `RegClass` the register allocator should pin a constrained
+    /// operand of this kind to.
+    ///
+    /// `Gpr`/`Fpr` map to the low-8-register subclasses (`GPR8`/`FPR8`),
+    /// not the full 16-register `GPR`/`FPR` classes: `patch_register` only
+    /// has a placeholder for the ModRM reg field, not for a REX prefix, so
+    /// the allocator must never choose `r8-r15`/`xmm8-xmm15` here. `Mask`
+    /// is unrestricted since all 8 `k` registers already fit in that field
+    /// without REX.
+    pub fn to_reg_class(&self) -> RegClass {
+        match *self {
+            AsmRegClass::Gpr => GPR8,
+            AsmRegClass::Fpr => FPR8,
+            AsmRegClass::Mask => MASK,
+        }
+    }
+}
+
+/// Emit the machine code for an `InlineAsm` instruction: patch the
+/// allocated register encoding for each operand into the template at its
+/// recorded byte offset, then copy the whole thing to `sink` verbatim.
+pub fn emit_inline_asm(func: &Function, inst: Inst, divert: &mut RegDiversions, sink: &mut CodeSink) {
+    let (template, operands) = match func.dfg[inst] {
+        InstructionData::InlineAsm {
+            ref template,
+            ref operands,
+            ..
+        } => (template, operands),
+        ref data => panic!("expected an InlineAsm instruction, got {:?}", data),
+    };
+
+    let mut code = template.clone();
+    for operand in operands.iter() {
+        let unit = divert.reg(operand.value, &func.locations);
+        patch_register(&mut code, operand.byte_offset, unit);
+    }
+
+    for byte in code {
+        sink.put1(byte);
+    }
+}
+
+/// Patch the register-number bits for `unit` into `code[offset]`.
+///
+/// This fills in the ModRM reg field (bits 5-3, mask `0x38`), not the R/M
+/// field -- an `InlineAsm` operand's placeholder always stands in for the
+/// reg field, since the R/M field (and any accompanying SIB/disp bytes) is
+/// fixed by the template itself. Only the low 3 bits of the register number
+/// fit there; the 4th bit (for `r8-r15`, `xmm8-xmm15`) belongs in the
+/// instruction's REX prefix, which this template has no placeholder for.
+/// `AsmRegClass::to_reg_class` keeps a register-class-constrained operand
+/// out of that situation by never allocating it one of those registers;
+/// an operand explicitly constrained to one specific register is the
+/// frontend's own choice; it's responsible for baking in any REX prefix
+/// that register needs.
+fn patch_register(code: &mut Vec<u8>, offset: usize, unit: RegUnit) {
+    let placeholder = code[offset];
+    code[offset] = (placeholder & 0xc7) | (((unit as u8) & 0x07) << 3);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patches_reg_field_not_rm_field() {
+        let mut code = vec![0x00u8];
+        patch_register(&mut code, 0, 3);
+        // reg=3 (011) belongs in bits 5-3; bits 2-0 (the R/M field) and the
+        // mod bits stay untouched.
+        assert_eq!(code[0], 0b0001_1000);
+    }
+
+    #[test]
+    fn preserves_mod_and_rm_bits() {
+        let mut code = vec![0b1100_0101u8]; // mod=11, reg=xxx, rm=101
+        patch_register(&mut code, 0, 6);
+        assert_eq!(code[0], 0b1100_0101 & 0xc7 | (6 << 3));
+        assert_eq!(code[0] & 0xc7, 0b1100_0101 & 0xc7);
+    }
+}
@@ -0,0 +1,43 @@
+//! Emission of binary Intel machine code.
+
+use super::asm;
+use super::enc_tables::{INFO, RECIPE_FUNCS};
+use super::registers::RU;
+use binemit::{bad_encoding, CodeSink};
+use ir::{Function, Inst, Opcode};
+use isa::RegUnit;
+use regalloc::RegDiversions;
+
+/// Offsets and names for the relocations used by the Intel backend. Indexed
+/// by `Reloc::to_index()`. Kept in sync with `enum Reloc` in
+/// `lib/cretonne/meta/cretonne/isa/intel.py`.
+pub static RELOC_NAMES: [&'static str; 4] = ["Call", "PCRel4", "Abs8", "X86CallPCRel4"];
+
+/// Emit binary machine code for `inst` into `sink`.
+///
+/// This dispatches on `inst`'s assigned encoding recipe, which was chosen by
+/// `Isa::legal_encodings` during legalization. Each recipe in
+/// `enc_tables::RECIPE_FUNCS` knows how to read the instruction's operands
+/// (after applying `divert`) and bytes to emit.
+pub fn emit_inst(
+    func: &Function,
+    inst: Inst,
+    divert: &mut RegDiversions,
+    sink: &mut CodeSink,
+) {
+    // `InlineAsm` has no fixed opcode or encoding recipe bit pattern: its
+    // bytes come from its own template, not from `RECIPE_FUNCS`.
+    if func.dfg[inst].opcode() == Opcode::InlineAsm {
+        asm::emit_inline_asm(func, inst, divert, sink);
+        return;
+    }
+
+    let encoding = func.encodings[inst];
+    if !encoding.is_legal() {
+        bad_encoding(func, inst);
+    }
+
+    let recipe = encoding.recipe();
+    let emitter = RECIPE_FUNCS[recipe];
+    emitter(func, inst, divert, sink, &INFO);
+}
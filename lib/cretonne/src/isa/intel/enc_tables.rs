@@ -0,0 +1,20 @@
+//! Intel encoding tables.
+//!
+//! The level-1/level-2 dispatch tables, encoding lists, legalization
+//! actions and predicates are generated from the recipes and instruction
+//! definitions by `lib/cretonne/meta`.
+//!
+//! `InlineAsm` instructions are not emitted through `RECIPE_FUNCS`: they
+//! have no fixed opcode of their own, and `binemit::emit_inst` hands them to
+//! `asm::emit_inline_asm`, which copies the instruction's own template,
+//! before any recipe is looked up.
+
+use super::registers::*;
+use bitset::BitSet;
+use ir;
+use isa;
+use isa::enc_tables::{Level1Entry, Level2Entry};
+use isa::encoding::{Encoding, RecipeSizing};
+use predicates;
+
+include!(concat!(env!("OUT_DIR"), "/encoding-intel.rs"));
@@ -2,10 +2,13 @@
 
 pub mod settings;
 mod abi;
+mod asm;
 mod binemit;
 mod enc_tables;
 mod registers;
+mod unwind;
 
+use self::unwind::UnwindInfo;
 use binemit::{CodeSink, MemoryCodeSink, emit_function};
 use super::super::settings as shared_settings;
 use isa::enc_tables::{self as shared_enc_tables, lookup_enclist, Encodings};
@@ -98,7 +101,7 @@ impl TargetIsa for Isa {
     }
 
     fn allocatable_registers(&self, func: &ir::Function) -> regalloc::AllocatableSet {
-        abi::allocatable_registers(func, &self.shared_flags)
+        abi::allocatable_registers(func, &self.shared_flags, &self.isa_flags)
     }
 
     fn emit_inst(
@@ -119,6 +122,15 @@ impl TargetIsa for Isa {
         &binemit::RELOC_NAMES
     }
 
+    fn unwind_info(&self, func: &ir::Function) -> Option<UnwindInfo> {
+        let word_size = if self.flags().is_64bit() { 8 } else { 4 };
+        Some(unwind::compute_unwind_info(
+            func,
+            word_size,
+            self.isa_flags.omit_frame_pointer(),
+        ))
+    }
+
     fn prologue_epilogue(&self, func: &mut ir::Function) -> result::CtonResult {
         let word_size = if self.flags().is_64bit() { 8 } else { 4 };
         let csr_type = if self.flags().is_64bit() {
@@ -126,8 +138,13 @@ impl TargetIsa for Isa {
         } else {
             ir::types::I32
         };
-        let csrs = abi::callee_saved_registers(&self.shared_flags);
-        let csr_stack_size = ((csrs.len() + 1) * word_size as usize) as i32;
+        let call_conv = func.signature.call_conv;
+        let omit_frame_pointer = self.isa_flags.omit_frame_pointer();
+        let csrs = abi::callee_saved_registers(call_conv, omit_frame_pointer);
+        let fpr_csrs = abi::callee_saved_fprs(call_conv);
+        let fp_slots = if omit_frame_pointer { 0 } else { 1 };
+        let csr_stack_size =
+            ((csrs.len() + fp_slots) * word_size as usize + fpr_csrs.len() * 16) as i32;
 
         func.create_stack_slot(ir::StackSlotData {
             kind: ir::StackSlotKind::IncomingArg,
@@ -135,17 +152,30 @@ impl TargetIsa for Isa {
             offset: -csr_stack_size,
         });
 
+        if call_conv == ir::CallConv::WindowsFastcall {
+            // The caller has already reserved this "home"/shadow space for
+            // the register-passed arguments; we only need to account for it
+            // when computing offsets, not allocate it ourselves.
+            func.create_stack_slot(ir::StackSlotData {
+                kind: ir::StackSlotKind::IncomingArg,
+                size: abi::FASTCALL_SHADOW_SPACE as u32,
+                offset: -csr_stack_size - abi::FASTCALL_SHADOW_SPACE as i32,
+            });
+        }
+
         let total_stack_size = layout_stack(&mut func.stack_slots, word_size)? as i32;
         let local_stack_size = (total_stack_size - csr_stack_size) as i64;
 
         // Add CSRs to function signature
-        let fp_arg = ir::AbiParam::special_reg(
-            csr_type,
-            ir::ArgumentPurpose::FramePointer,
-            RU::rbp as RegUnit,
-        );
-        func.signature.params.push(fp_arg);
-        func.signature.returns.push(fp_arg);
+        if !omit_frame_pointer {
+            let fp_arg = ir::AbiParam::special_reg(
+                csr_type,
+                ir::ArgumentPurpose::FramePointer,
+                RU::rbp as RegUnit,
+            );
+            func.signature.params.push(fp_arg);
+            func.signature.returns.push(fp_arg);
+        }
 
         for csr in csrs.iter() {
             let csr_arg = ir::AbiParam::special_reg(
@@ -157,53 +187,194 @@ impl TargetIsa for Isa {
             func.signature.returns.push(csr_arg);
         }
 
+        let fpr_csr_type = ir::types::F64X2;
+        for csr in fpr_csrs.iter() {
+            let csr_arg =
+                ir::AbiParam::special_reg(fpr_csr_type, ir::ArgumentPurpose::CalleeSaved, *csr as RegUnit);
+            func.signature.params.push(csr_arg);
+            func.signature.returns.push(csr_arg);
+        }
 
         let entry_ebb = func.layout.entry_block().expect("missing entry block");
         let mut pos = EncCursor::new(func, self).at_first_insertion_point(entry_ebb);
 
-        self.insert_prologue(&mut pos, local_stack_size, csr_type);
-        self.insert_epilogues(&mut pos, local_stack_size, csr_type);
+        // With the frame pointer omitted, there is no separate push for the
+        // CSR area: the single frame allocation below must cover it too, so
+        // every stack slot and CSR save stays at a constant offset from
+        // `rsp` for the lifetime of the function.
+        let frame_stack_size = if omit_frame_pointer {
+            total_stack_size as i64
+        } else {
+            local_stack_size
+        };
+
+        self.insert_prologue(&mut pos, frame_stack_size, csr_type, csr_stack_size, word_size);
+        self.insert_epilogues(&mut pos, frame_stack_size, csr_type, csr_stack_size, word_size);
 
         Ok(())
     }
 }
 
+/// Size in bytes of an OS guard page. A frame allocation at or above this
+/// size can step over the guard page in a single `adjust_sp_imm`, so it
+/// must be probed a page at a time instead.
+const PROBESTACK_PAGE_SIZE: u64 = 4096;
+
+/// Above this many pages, probing inline would bloat the prologue; call out
+/// to `__cranelift_probestack` instead.
+const PROBESTACK_UNROLL_MAX_PAGES: u64 = 8;
+
+/// Offset of the `i`-th callee-saved GPR's slot within the CSR save area,
+/// relative to the same virtual frame-top coordinate every other stack slot
+/// in this file is offset from. The CSR area itself is the topmost
+/// `csr_stack_size` bytes of the frame, just below offset 0 (see the `IncomingArg` slot created
+/// in `prologue_epilogue`), so this is always relative to `-csr_stack_size`,
+/// never to the overall frame's `stack_size`.
+fn csr_slot_offset(csr_stack_size: i32, word_size: i32, i: i32) -> i32 {
+    -csr_stack_size + i * word_size
+}
+
 impl Isa {
-    fn insert_prologue(&self, pos: &mut EncCursor, stack_size: i64, csr_type: ir::types::Type) {
-        // Append param to entry EBB
+    /// Probe every page of a `stack_size`-byte frame allocation as the
+    /// stack pointer descends through it, so that an allocation large
+    /// enough to jump over the guard page can't go unnoticed by the OS.
+    fn insert_stack_probe(&self, pos: &mut EncCursor, stack_size: i64) {
+        let num_pages = stack_size as u64 / PROBESTACK_PAGE_SIZE;
+        let residual = stack_size as u64 % PROBESTACK_PAGE_SIZE;
+
+        if num_pages <= PROBESTACK_UNROLL_MAX_PAGES {
+            let zero = pos.ins().iconst(ir::types::I32, 0);
+            // This pass runs after regalloc, so every value it creates
+            // needs an explicit location, same as `size` in the call-out
+            // path below; `rax` is free to clobber this early in the
+            // prologue.
+            pos.func.locations[zero] = ir::ValueLoc::Reg(RU::rax as RegUnit);
+            for page in 1..=num_pages {
+                let offset = -((page * PROBESTACK_PAGE_SIZE) as i32);
+                let probe_slot = pos.func.create_stack_slot(ir::StackSlotData {
+                    kind: ir::StackSlotKind::ExplicitSlot,
+                    size: 4,
+                    offset,
+                });
+                pos.ins().stack_store(zero, probe_slot, 0);
+            }
+            if residual > 0 {
+                let offset = -(stack_size as i32);
+                let probe_slot = pos.func.create_stack_slot(ir::StackSlotData {
+                    kind: ir::StackSlotKind::ExplicitSlot,
+                    size: 4,
+                    offset,
+                });
+                pos.ins().stack_store(zero, probe_slot, 0);
+            }
+            pos.ins().adjust_sp_imm(Imm64::new(-stack_size));
+        } else {
+            let call_conv = pos.func.signature.call_conv;
+            let probestack = abi::get_probestack_funcref(pos.func, call_conv);
+            let size = pos.ins().iconst(ir::types::I64, stack_size);
+            pos.func.locations[size] = ir::ValueLoc::Reg(abi::PROBESTACK_ARG_REG as RegUnit);
+            pos.ins().call(probestack, &[size]);
+            pos.ins().adjust_sp_imm(Imm64::new(-stack_size));
+        }
+    }
+
+    fn insert_prologue(
+        &self,
+        pos: &mut EncCursor,
+        stack_size: i64,
+        csr_type: ir::types::Type,
+        csr_stack_size: i32,
+        word_size: i32,
+    ) {
+        let omit_frame_pointer = self.isa_flags.omit_frame_pointer();
         let ebb = pos.current_ebb().expect("missing ebb under cursor");
-        let fp = pos.func.dfg.append_ebb_param(ebb, csr_type);
-        pos.func.locations[fp] = ir::ValueLoc::Reg(RU::rbp as RegUnit);
+        let call_conv = pos.func.signature.call_conv;
 
-        pos.ins().x86_push(fp);
-        pos.ins().copy_special(
-            RU::rsp as RegUnit,
-            RU::rbp as RegUnit,
-        );
+        if !omit_frame_pointer {
+            // Append param to entry EBB
+            let fp = pos.func.dfg.append_ebb_param(ebb, csr_type);
+            pos.func.locations[fp] = ir::ValueLoc::Reg(RU::rbp as RegUnit);
+
+            pos.ins().x86_push(fp);
+            pos.ins().copy_special(
+                RU::rsp as RegUnit,
+                RU::rbp as RegUnit,
+            );
+        }
 
         if stack_size > 0 {
-            pos.ins().adjust_sp_imm(Imm64::new(-stack_size));
+            if self.isa_flags.enable_probestack() && stack_size as u64 >= PROBESTACK_PAGE_SIZE {
+                self.insert_stack_probe(pos, stack_size);
+            } else {
+                pos.ins().adjust_sp_imm(Imm64::new(-stack_size));
+            }
         }
 
-        let csrs = abi::callee_saved_registers(&self.shared_flags);
-        for reg in csrs.iter() {
+        // The CSR area reserved by `prologue_epilogue` is the `IncomingArg`
+        // slot at offset `-csr_stack_size`: the topmost `csr_stack_size`
+        // bytes of the frame, regardless of how `rsp` got there. That's the
+        // same coordinate system every other slot offset in this file uses,
+        // so it's also the base every explicit CSR save below is relative
+        // to -- not `stack_size`, which has nothing to do with where this
+        // sub-area sits.
+        let csr_base = -csr_stack_size;
+
+        let csrs = abi::callee_saved_registers(call_conv, omit_frame_pointer);
+        for (i, reg) in csrs.iter().enumerate() {
             // Append param to entry EBB
             let csr_arg = pos.func.dfg.append_ebb_param(ebb, csr_type);
 
             // Assign it a location
             pos.func.locations[csr_arg] = ir::ValueLoc::Reg(*reg as RegUnit);
 
-            // Remember it so we can push it momentarily
-            pos.ins().x86_push(csr_arg);
+            if omit_frame_pointer {
+                let offset = csr_slot_offset(csr_stack_size, word_size, i as i32);
+                let slot = pos.func.create_stack_slot(ir::StackSlotData {
+                    kind: ir::StackSlotKind::ExplicitSlot,
+                    size: word_size as u32,
+                    offset,
+                });
+                pos.ins().stack_store(csr_arg, slot, 0);
+            } else {
+                // Remember it so we can push it momentarily
+                pos.ins().x86_push(csr_arg);
+            }
+        }
+
+        // The Windows fastcall convention additionally treats `xmm6-xmm15`
+        // as callee-saved. x86-64 has no push/pop encoding for XMM
+        // registers, so -- unlike the GPR CSRs above -- these always go
+        // through an explicit stack slot in the fixed-size region we
+        // reserved for them in `prologue_epilogue`, regardless of whether
+        // the frame pointer is omitted.
+        let fpr_csrs = abi::callee_saved_fprs(call_conv);
+        let fpr_base = csr_base + (csrs.len() as i32) * word_size;
+        for (i, reg) in fpr_csrs.iter().enumerate() {
+            let csr_arg = pos.func.dfg.append_ebb_param(ebb, ir::types::F64X2);
+            pos.func.locations[csr_arg] = ir::ValueLoc::Reg(*reg as RegUnit);
+            let offset = fpr_base + (i as i32) * 16;
+            let slot = pos.func.create_stack_slot(ir::StackSlotData {
+                kind: ir::StackSlotKind::ExplicitSlot,
+                size: 16,
+                offset,
+            });
+            pos.ins().stack_store(csr_arg, slot, 0);
         }
     }
 
-    fn insert_epilogues(&self, pos: &mut EncCursor, stack_size: i64, csr_type: ir::types::Type) {
+    fn insert_epilogues(
+        &self,
+        pos: &mut EncCursor,
+        stack_size: i64,
+        csr_type: ir::types::Type,
+        csr_stack_size: i32,
+        word_size: i32,
+    ) {
         while let Some(ebb) = pos.next_ebb() {
             pos.goto_last_inst(ebb);
             if let Some(inst) = pos.current_inst() {
                 if pos.func.dfg[inst].opcode().is_return() {
-                    self.insert_epilogue(inst, stack_size, pos, csr_type);
+                    self.insert_epilogue(inst, stack_size, pos, csr_type, csr_stack_size, word_size);
                 }
             }
         }
@@ -216,7 +387,55 @@ impl Isa {
         stack_size: i64,
         pos: &mut EncCursor,
         csr_type: ir::types::Type,
+        csr_stack_size: i32,
+        word_size: i32,
     ) {
+        let omit_frame_pointer = self.isa_flags.omit_frame_pointer();
+        let call_conv = pos.func.signature.call_conv;
+        // See the matching comment in `insert_prologue`: this is relative to
+        // the reserved CSR-area slot, not to `stack_size`.
+        let csr_base = -csr_stack_size;
+        let csrs = abi::callee_saved_registers(call_conv, omit_frame_pointer);
+        let fpr_csrs = abi::callee_saved_fprs(call_conv);
+        let fpr_base = csr_base + (csrs.len() as i32) * word_size;
+
+        if omit_frame_pointer {
+            // Insert the frame release first and step back over it, so the
+            // CSR restores below land ahead of it and still address their
+            // slots from the post-prologue `rsp`.
+            if stack_size > 0 {
+                pos.ins().adjust_sp_imm(Imm64::new(stack_size));
+                pos.prev_inst();
+            }
+
+            for (i, reg) in fpr_csrs.iter().enumerate() {
+                let offset = fpr_base + (i as i32) * 16;
+                let slot = pos.func.create_stack_slot(ir::StackSlotData {
+                    kind: ir::StackSlotKind::ExplicitSlot,
+                    size: 16,
+                    offset,
+                });
+                let csr_ret = pos.ins().stack_load(ir::types::F64X2, slot, 0);
+                pos.prev_inst();
+                pos.func.locations[csr_ret] = ir::ValueLoc::Reg(*reg as RegUnit);
+                pos.func.dfg.append_inst_arg(inst, csr_ret);
+            }
+
+            for (i, reg) in csrs.iter().enumerate() {
+                let offset = csr_slot_offset(csr_stack_size, word_size, i as i32);
+                let slot = pos.func.create_stack_slot(ir::StackSlotData {
+                    kind: ir::StackSlotKind::ExplicitSlot,
+                    size: word_size as u32,
+                    offset,
+                });
+                let csr_ret = pos.ins().stack_load(csr_type, slot, 0);
+                pos.prev_inst();
+                pos.func.locations[csr_ret] = ir::ValueLoc::Reg(*reg as RegUnit);
+                pos.func.dfg.append_inst_arg(inst, csr_ret);
+            }
+            return;
+        }
+
         if stack_size > 0 {
             pos.ins().adjust_sp_imm(Imm64::new(stack_size));
         }
@@ -227,7 +446,6 @@ impl Isa {
         pos.func.locations[fp_ret] = ir::ValueLoc::Reg(RU::rbp as RegUnit);
         pos.func.dfg.append_inst_arg(inst, fp_ret);
 
-        let csrs = abi::callee_saved_registers(&self.shared_flags);
         for reg in csrs.iter() {
             let csr_ret = pos.ins().x86_pop(csr_type);
             pos.prev_inst();
@@ -235,5 +453,52 @@ impl Isa {
             pos.func.locations[csr_ret] = ir::ValueLoc::Reg(*reg as RegUnit);
             pos.func.dfg.append_inst_arg(inst, csr_ret);
         }
+
+        // XMM CSRs have no legal pop encoding, so they're always restored
+        // from their explicit stack slot rather than popped.
+        for (i, reg) in fpr_csrs.iter().enumerate() {
+            let offset = fpr_base + (i as i32) * 16;
+            let slot = pos.func.create_stack_slot(ir::StackSlotData {
+                kind: ir::StackSlotKind::ExplicitSlot,
+                size: 16,
+                offset,
+            });
+            let csr_ret = pos.ins().stack_load(ir::types::F64X2, slot, 0);
+            pos.prev_inst();
+
+            pos.func.locations[csr_ret] = ir::ValueLoc::Reg(*reg as RegUnit);
+            pos.func.dfg.append_inst_arg(inst, csr_ret);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::csr_slot_offset;
+
+    #[test]
+    fn csr_slot_offset_stays_within_the_frame() {
+        // One CSR, no locals: `csr_stack_size == total_stack_size == 8`
+        // (a single 8-byte GPR, word_size 8). After the frame's single
+        // `adjust_sp_imm(-8)`, `rsp` sits 8 bytes below the virtual
+        // frame-top coordinate (offset 0), so the only in-bounds offset for
+        // this CSR's slot is -8.
+        let csr_stack_size = 8;
+        let word_size = 8;
+        assert_eq!(csr_slot_offset(csr_stack_size, word_size, 0), -8);
+    }
+
+    #[test]
+    fn csr_slot_offset_is_never_positive_or_zero() {
+        // The old `stack_size - csr_stack_size` formula degenerated to 0
+        // in the no-locals case above, addressing one byte above the
+        // frame's own allocation (the caller's territory) instead of
+        // inside it.
+        for csr_stack_size in &[8, 16, 24] {
+            // Only indices of CSRs that fit in the area are meaningful.
+            for i in 0..(*csr_stack_size / 8) {
+                assert!(csr_slot_offset(*csr_stack_size, 8, i) < 0);
+            }
+        }
     }
 }
@@ -0,0 +1,23 @@
+//! Intel register descriptions.
+//!
+//! The register banks and classes for this ISA are generated from the
+//! shared register definitions by `lib/cretonne/meta`; this file only
+//! glues the generated tables into the `intel` module.
+//!
+//! In addition to the `GPR` and `FPR` banks, the generated tables define a
+//! `MASK` class covering the AVX-512 `k0-k7` predicate registers. These
+//! registers aren't used by any encoding recipe on their own; they only
+//! show up as operands of masked instructions and as register-class
+//! constraints on `InlineAsm` operands.
+//!
+//! `GPR8` and `FPR8` are the low-8-register subclasses of `GPR` and `FPR`
+//! (`rax`-`rdi`, `xmm0`-`xmm7`) -- the registers encodable in a ModRM reg
+//! field without a REX prefix. `InlineAsm` uses these, rather than the full
+//! 16-register classes, for its register-class-constrained operands: its
+//! template has a placeholder byte for the ModRM reg field but none for a
+//! REX prefix, so the allocator must never hand such an operand a register
+//! whose encoding needs one.
+
+use isa::registers::{RegBank, RegClassData, RegInfo};
+
+include!(concat!(env!("OUT_DIR"), "/registers-intel.rs"));
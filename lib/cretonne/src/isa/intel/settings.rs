@@ -0,0 +1,11 @@
+//! Intel-specific settings.
+//!
+//! Notable boolean flags defined here (see `meta/cretonne/isa/intel/settings.py`):
+//!
+//! - `enable_probestack`: emit stack probes for frames that may cross the
+//!   guard page, instead of a single `adjust_sp_imm`.
+//! - `omit_frame_pointer`: don't maintain `rbp` as a frame pointer; address
+//!   everything as a constant offset from `rsp` instead, freeing `rbp` up
+//!   as an allocatable GPR.
+
+include!(concat!(env!("OUT_DIR"), "/settings-intel.rs"));
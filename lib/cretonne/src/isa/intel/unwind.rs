@@ -0,0 +1,490 @@
+//! Unwind information for Intel-generated prologues.
+//!
+//! Stack unwinders and profilers need to know, at every code offset inside
+//! a prologue, how to recover the Canonical Frame Address (CFA) and where
+//! each callee-saved register was stashed, so that they can walk back into
+//! the caller. `insert_prologue` only ever emits one of a handful of fixed
+//! instruction shapes (an optional `x86_push(rbp)` / `copy_special` pair, an
+//! `adjust_sp_imm`, a run of `x86_push`es, and -- for XMM CSRs, or any CSR
+//! at all in omit-frame-pointer mode -- a run of `stack_store`s into the
+//! reserved CSR area), so rather than running a general dataflow analysis
+//! we just replay that shape here.
+
+use super::registers::RU;
+use ir;
+
+/// Unwind information for a single function, ready to be registered with
+/// the platform unwinder.
+pub enum UnwindInfo {
+    /// A `.eh_frame`-style CIE/FDE pair, used on System V platforms.
+    SystemV { cie: Vec<u8>, fde: Vec<u8> },
+    /// A Windows x64 `UNWIND_INFO` table, used with the fastcall ABI.
+    Windows(Vec<u8>),
+}
+
+/// One row of the unwind table: "starting at `offset` bytes into the
+/// function, the CFA and the listed registers are found this way".
+struct UnwindRow {
+    offset: u32,
+    cfa_reg: RU,
+    cfa_offset: i32,
+    /// How far `rsp` sits below the CFA at this point. Unlike `cfa_offset`
+    /// this keeps changing after the frame pointer takes over, which is how
+    /// the Windows encoder tells a plain stack allocation (`rsp_offset`
+    /// moves, `saved` doesn't grow) apart from a push or CSR save.
+    rsp_offset: i32,
+    /// `(register, offset from CFA)` for every register saved so far.
+    saved: Vec<(RU, i32)>,
+}
+
+/// Compute unwind information for `func`, whose prologue was produced by
+/// `Isa::insert_prologue` for the given word size (8 on amd64) and
+/// frame-pointer-omission setting.
+pub fn compute_unwind_info(
+    func: &ir::Function,
+    word_size: i32,
+    omit_frame_pointer: bool,
+) -> UnwindInfo {
+    let rows = prologue_rows(func, word_size, omit_frame_pointer);
+    match func.signature.call_conv {
+        ir::CallConv::WindowsFastcall => UnwindInfo::Windows(windows_unwind_info(&rows)),
+        _ => {
+            let cie = system_v_cie(word_size);
+            let fde = system_v_fde(&rows);
+            UnwindInfo::SystemV { cie, fde }
+        }
+    }
+}
+
+/// Replay the prologue instructions of `func`'s entry block, producing an
+/// `UnwindRow` for the function entry and then one per instruction that
+/// moves `rsp`, changes the CFA rule or saves a register. Code offsets are approximated by summing a fixed, conservative
+/// size per instruction kind, since this module doesn't have access to the
+/// real encoder; a full implementation would read the sizes `emit_inst`
+/// actually produced.
+fn prologue_rows(func: &ir::Function, word_size: i32, omit_frame_pointer: bool) -> Vec<UnwindRow> {
+    let mut rows = Vec::new();
+    let mut offset = 0u32;
+    let mut cfa_reg = RU::rsp;
+    let mut cfa_offset = word_size; // the return address pushed by `call`.
+    // How far the current `rsp` sits below the CFA. This keeps growing with
+    // every push regardless of `cfa_reg`: once the frame pointer takes over
+    // the CFA stays fixed at `rbp`'s own distance from it, but a pushed
+    // register's *save* location is still wherever `rsp` lands, so push
+    // offsets are always computed from this rather than from `cfa_offset`.
+    let mut rsp_offset = word_size;
+    let mut saved = Vec::new();
+
+    rows.push(UnwindRow {
+        offset,
+        cfa_reg,
+        cfa_offset,
+        rsp_offset,
+        saved: saved.clone(),
+    });
+
+    let entry_ebb = match func.layout.entry_block() {
+        Some(ebb) => ebb,
+        None => return rows,
+    };
+
+    for inst in func.layout.ebb_insts(entry_ebb) {
+        let opcode = func.dfg[inst].opcode();
+        if opcode == ir::Opcode::X86Push {
+            rsp_offset += word_size;
+            offset += 1;
+            if cfa_reg == RU::rsp {
+                cfa_offset = rsp_offset;
+            }
+            if !omit_frame_pointer && cfa_reg == RU::rsp && saved.is_empty() {
+                // This is the `push rbp` that establishes the frame.
+                saved.push((RU::rbp, -rsp_offset));
+            } else {
+                let reg = value_register(func, pushed_value(func, inst));
+                saved.push((reg, -rsp_offset));
+            }
+            rows.push(UnwindRow {
+                offset,
+                cfa_reg,
+                cfa_offset,
+                rsp_offset,
+                saved: saved.clone(),
+            });
+        } else if opcode == ir::Opcode::CopySpecial {
+            // From here on the CFA is `rbp`-relative and fixed: `rbp` itself
+            // never moves again, so `cfa_offset` (already `rsp_offset` as of
+            // the `push rbp` above) stops changing, even though `rsp` keeps
+            // descending for the CSR pushes and the locals allocation.
+            cfa_reg = RU::rbp;
+            offset += 3;
+            rows.push(UnwindRow {
+                offset,
+                cfa_reg,
+                cfa_offset,
+                rsp_offset,
+                saved: saved.clone(),
+            });
+        } else if opcode == ir::Opcode::AdjustSpImm {
+            offset += 7;
+            if let Some(imm) = func.dfg[inst].imm_value() {
+                let delta: i64 = imm.into();
+                rsp_offset -= delta as i32;
+                if cfa_reg == RU::rsp {
+                    cfa_offset = rsp_offset;
+                }
+            }
+            rows.push(UnwindRow {
+                offset,
+                cfa_reg,
+                cfa_offset,
+                rsp_offset,
+                saved: saved.clone(),
+            });
+        } else if opcode == ir::Opcode::Iconst || opcode == ir::Opcode::Call {
+            // `insert_stack_probe` emits these ahead of the real frame
+            // allocation when guard-page probing is enabled: an `iconst`
+            // zero (unrolled probing) or a `call` to the probe routine
+            // (the page-at-a-time fallback), neither of which changes the
+            // CFA or saves a register. Skip them rather than treating them
+            // as the end of the prologue, so the `adjust_sp_imm` and CSR
+            // saves that follow still get replayed.
+            continue;
+        } else if opcode == ir::Opcode::StackStore {
+            // `insert_stack_probe`'s unrolled path also emits `stack_store`,
+            // to write a zero into each probed page -- that's not a CSR
+            // save, so don't let it end up in `saved`. A real CSR save
+            // always stores one of the entry EBB's own parameters (that's
+            // how `insert_prologue` receives the incoming CSR values);
+            // the probe's zero is the result of an `iconst` instead.
+            let (value, stack_slot) = stack_store_operands(func, inst);
+            if !func.dfg.ebb_params(entry_ebb).contains(&value) {
+                continue;
+            }
+
+            // `insert_prologue` uses this for every CSR save in
+            // omit-frame-pointer mode, and for the XMM CSRs regardless of
+            // frame-pointer mode -- both cases are explicit-offset saves
+            // into the reserved CSR area rather than pushes, so recover the
+            // register and its CFA-relative offset from the target slot.
+            let reg = value_register(func, value);
+            let is_xmm = func.dfg.value_type(value) == ir::types::F64X2;
+            offset += if is_xmm { 9 } else { 8 };
+            let slot_offset = func.stack_slots[stack_slot].offset;
+            // Slot offsets are relative to the same virtual frame-top
+            // coordinate as `rsp` was before the prologue's frame
+            // allocation, i.e. `word_size` bytes below the CFA.
+            let reg_offset = slot_offset - word_size;
+            saved.push((reg, reg_offset));
+            rows.push(UnwindRow {
+                offset,
+                cfa_reg,
+                cfa_offset,
+                rsp_offset,
+                saved: saved.clone(),
+            });
+        } else if opcode.is_return() {
+            break;
+        } else {
+            // Anything else marks the end of the prologue proper.
+            break;
+        }
+    }
+
+    rows
+}
+
+fn pushed_value(func: &ir::Function, inst: ir::Inst) -> ir::Value {
+    func.dfg.inst_args(inst)[0]
+}
+
+fn value_register(func: &ir::Function, value: ir::Value) -> RU {
+    match func.locations[value] {
+        ir::ValueLoc::Reg(unit) => RU::from_unit(unit),
+        _ => panic!("value saved in the prologue has no register location"),
+    }
+}
+
+fn stack_store_operands(func: &ir::Function, inst: ir::Inst) -> (ir::Value, ir::StackSlot) {
+    match func.dfg[inst] {
+        ir::InstructionData::StackStore {
+            arg, stack_slot, ..
+        } => (arg, stack_slot),
+        ref data => panic!("expected a StackStore instruction, got {:?}", data),
+    }
+}
+
+/// Build a minimal CIE describing the `rsp`-relative entry state shared by
+/// every function.
+fn system_v_cie(word_size: i32) -> Vec<u8> {
+    let mut cie = Vec::new();
+    cie.push(1); // CIE version
+    // No augmentation: an empty, nul-terminated augmentation string means no
+    // augmentation data follows here or in the FDE, unlike e.g. `"zR\0"`,
+    // which would additionally require a ULEB128 augmentation-data length
+    // and an FDE-pointer-encoding byte that nothing here produces.
+    cie.push(0);
+    cie.push(1); // code alignment factor
+    // Data alignment factor, signed LEB128, single byte here. `-word_size`
+    // (-8) is `0x78`: masking to 7 bits keeps the continuation bit (0x80)
+    // clear, which a plain `as u8` cast does not (`-8i32 as u8 == 0xf8`).
+    cie.push(((-word_size) & 0x7f) as u8);
+    cie.push(dwarf_reg(RU::rip) as u8); // return address register
+    // DW_CFA_def_cfa rsp, word_size
+    cie.push(0x0c); // DW_CFA_def_cfa
+    cie.push(dwarf_reg(RU::rsp) as u8);
+    cie.push(word_size as u8);
+    cie
+}
+
+/// Build an FDE from the replayed unwind rows: one `DW_CFA_advance_loc`
+/// plus `DW_CFA_def_cfa*`/`DW_CFA_offset` per row after the first.
+fn system_v_fde(rows: &[UnwindRow]) -> Vec<u8> {
+    let mut fde = Vec::new();
+    let mut last_offset = 0u32;
+    let mut last_cfa_reg = RU::rsp;
+    let mut last_cfa_offset = rows.first().map_or(0, |r| r.cfa_offset);
+    let mut last_saved = 0;
+
+    for row in rows {
+        let advance = row.offset - last_offset;
+        if advance > 0 {
+            fde.push(0x40 | (advance.min(0x3f) as u8)); // DW_CFA_advance_loc
+            last_offset = row.offset;
+        }
+
+        if row.cfa_reg != last_cfa_reg {
+            fde.push(0x0c); // DW_CFA_def_cfa
+            fde.push(dwarf_reg(row.cfa_reg) as u8);
+            fde.push(row.cfa_offset as u8);
+            last_cfa_reg = row.cfa_reg;
+            last_cfa_offset = row.cfa_offset;
+        } else if row.cfa_offset != last_cfa_offset {
+            fde.push(0x0e); // DW_CFA_def_cfa_offset
+            fde.push(row.cfa_offset as u8);
+            last_cfa_offset = row.cfa_offset;
+        }
+
+        for &(reg, reg_offset) in row.saved.iter().skip(last_saved) {
+            fde.push(0x80 | (dwarf_reg(reg) as u8 & 0x3f)); // DW_CFA_offset
+            fde.push((reg_offset / 8).abs() as u8);
+        }
+        last_saved = row.saved.len();
+    }
+
+    fde
+}
+
+/// Build a Windows x64 `UNWIND_INFO` table (see the x64 ABI documentation)
+/// from the replayed unwind rows. Each GPR push becomes a
+/// `UWOP_PUSH_NONVOL` unwind code; each XMM CSR save becomes a
+/// `UWOP_SAVE_XMM128` unwind code (with its extra scaled-offset slot); the
+/// stack allocation becomes a `UWOP_ALLOC_*` code.
+fn windows_unwind_info(rows: &[UnwindRow]) -> Vec<u8> {
+    let mut info = Vec::new();
+    info.push(1); // version 1, no flags
+    let prologue_size = rows.last().map_or(0, |r| r.offset);
+    info.push(prologue_size as u8);
+
+    // The scaled offset `UWOP_SAVE_XMM128` records is relative to `rsp`
+    // after the prologue's frame allocation has completed, not to the CFA.
+    let final_rsp_offset = rows.last().map_or(0, |r| r.rsp_offset);
+
+    let mut codes = Vec::new();
+    let mut node_count = 0u8;
+    for window in rows.windows(2) {
+        let (prev, cur) = (&window[0], &window[1]);
+        if cur.saved.len() > prev.saved.len() {
+            if let Some(&(reg, reg_offset)) = cur.saved.last() {
+                if is_xmm(reg) {
+                    let rsp_offset = final_rsp_offset + reg_offset;
+                    let scaled = (rsp_offset / 16) as u16;
+                    codes.push(cur.offset as u8);
+                    codes.push(0x08 | (windows_reg(reg) << 4)); // UWOP_SAVE_XMM128
+                    codes.push((scaled & 0xff) as u8);
+                    codes.push((scaled >> 8) as u8);
+                    node_count += 2;
+                } else {
+                    codes.push(cur.offset as u8);
+                    codes.push(0x00 | (windows_reg(reg) << 4)); // UWOP_PUSH_NONVOL
+                    node_count += 1;
+                }
+            }
+        } else if cur.rsp_offset != prev.rsp_offset {
+            // `adjust_sp_imm`'s frame allocation: no register is pushed or
+            // saved, but `rsp` moves, so it needs its own unwind code or the
+            // unwinder's RSP (and every `UWOP_SAVE_XMM128` offset computed
+            // from it above) would be wrong.
+            let (alloc_codes, alloc_nodes) = alloc_code(cur.offset, cur.rsp_offset - prev.rsp_offset);
+            codes.extend(alloc_codes);
+            node_count += alloc_nodes;
+        }
+    }
+
+    info.push(node_count);
+    info.push(0); // frame register / offset, unused (no FPO here)
+    info.extend(codes);
+    info
+}
+
+/// Build the unwind code(s) for a stack allocation of `size` bytes at
+/// prologue offset `offset`, as `UWOP_ALLOC_SMALL` or `UWOP_ALLOC_LARGE`
+/// depending on how big it is, along with the number of 2-byte unwind-code
+/// slots it occupies.
+fn alloc_code(offset: u32, size: i32) -> (Vec<u8>, u8) {
+    let mut code = vec![offset as u8];
+    if size <= 128 {
+        // UWOP_ALLOC_SMALL: info = size / 8 - 1, one slot.
+        code.push(2 | ((((size / 8) - 1) as u8) << 4));
+        (code, 1)
+    } else if size <= 0x7fff8 {
+        // UWOP_ALLOC_LARGE, info = 0: one extra slot holding size / 8.
+        code.push(1);
+        let scaled = (size / 8) as u16;
+        code.push((scaled & 0xff) as u8);
+        code.push((scaled >> 8) as u8);
+        (code, 2)
+    } else {
+        // UWOP_ALLOC_LARGE, info = 1: two extra slots holding the raw size.
+        code.push(1 | (1 << 4));
+        code.push((size & 0xff) as u8);
+        code.push(((size >> 8) & 0xff) as u8);
+        code.push(((size >> 16) & 0xff) as u8);
+        code.push(((size >> 24) & 0xff) as u8);
+        (code, 3)
+    }
+}
+
+fn is_xmm(reg: RU) -> bool {
+    match reg {
+        RU::xmm0
+        | RU::xmm1
+        | RU::xmm2
+        | RU::xmm3
+        | RU::xmm4
+        | RU::xmm5
+        | RU::xmm6
+        | RU::xmm7
+        | RU::xmm8
+        | RU::xmm9
+        | RU::xmm10
+        | RU::xmm11
+        | RU::xmm12
+        | RU::xmm13
+        | RU::xmm14
+        | RU::xmm15 => true,
+        _ => false,
+    }
+}
+
+fn dwarf_reg(reg: RU) -> u8 {
+    // DWARF x86-64 register numbering (System V ABI, section 3.6).
+    match reg {
+        RU::rax => 0,
+        RU::rdx => 1,
+        RU::rcx => 2,
+        RU::rbx => 3,
+        RU::rsi => 4,
+        RU::rdi => 5,
+        RU::rbp => 6,
+        RU::rsp => 7,
+        RU::r8 => 8,
+        RU::r9 => 9,
+        RU::r10 => 10,
+        RU::r11 => 11,
+        RU::r12 => 12,
+        RU::r13 => 13,
+        RU::r14 => 14,
+        RU::r15 => 15,
+        RU::rip => 16,
+        RU::xmm0 => 17,
+        RU::xmm1 => 18,
+        RU::xmm2 => 19,
+        RU::xmm3 => 20,
+        RU::xmm4 => 21,
+        RU::xmm5 => 22,
+        RU::xmm6 => 23,
+        RU::xmm7 => 24,
+        RU::xmm8 => 25,
+        RU::xmm9 => 26,
+        RU::xmm10 => 27,
+        RU::xmm11 => 28,
+        RU::xmm12 => 29,
+        RU::xmm13 => 30,
+        RU::xmm14 => 31,
+        RU::xmm15 => 32,
+        _ => panic!("no DWARF register number for {:?}", reg),
+    }
+}
+
+fn windows_reg(reg: RU) -> u8 {
+    // For GPRs, the Windows x64 UNWIND_INFO register numbering matches the
+    // encoding used for the ModRM/REX.B register field -- not the DWARF
+    // numbering `dwarf_reg` uses, which orders `rcx`/`rdx`/`rsi`/`rdi`/`rbp`
+    // differently. For XMM registers it's simply the xmm index (0-15),
+    // which isn't the same as the DWARF number (17-32) those share a
+    // `dwarf_reg` arm with.
+    if is_xmm(reg) {
+        dwarf_reg(reg) - 17
+    } else {
+        modrm_reg(reg)
+    }
+}
+
+fn modrm_reg(reg: RU) -> u8 {
+    // Hardware ModRM/REX.B register numbering (Intel SDM Vol. 2, Table 2-2).
+    match reg {
+        RU::rax => 0,
+        RU::rcx => 1,
+        RU::rdx => 2,
+        RU::rbx => 3,
+        RU::rsp => 4,
+        RU::rbp => 5,
+        RU::rsi => 6,
+        RU::rdi => 7,
+        RU::r8 => 8,
+        RU::r9 => 9,
+        RU::r10 => 10,
+        RU::r11 => 11,
+        RU::r12 => 12,
+        RU::r13 => 13,
+        RU::r14 => 14,
+        RU::r15 => 15,
+        _ => panic!("no ModRM register number for {:?}", reg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cie_data_alignment_factor_is_valid_sleb128() {
+        let cie = system_v_cie(8);
+        // Byte 3 is the data alignment factor; a single-byte SLEB128 value
+        // must have its continuation bit (0x80) clear.
+        assert_eq!(cie[3], 0x78);
+        assert_eq!(cie[3] & 0x80, 0);
+    }
+
+    #[test]
+    fn dwarf_reg_covers_xmm_registers() {
+        assert_eq!(dwarf_reg(RU::xmm0), 17);
+        assert_eq!(dwarf_reg(RU::xmm15), 32);
+    }
+
+    #[test]
+    fn windows_reg_xmm_index_is_zero_based() {
+        assert_eq!(windows_reg(RU::xmm6), 6);
+        assert_eq!(windows_reg(RU::xmm15), 15);
+    }
+
+    #[test]
+    fn windows_reg_gpr_uses_modrm_numbering_not_dwarf() {
+        // `rsi`/`rdi`/`rbp` are the registers where ModRM and DWARF numbering
+        // disagree; this would wrongly pass if `windows_reg` fell back to
+        // `dwarf_reg`.
+        assert_eq!(windows_reg(RU::rsi), 6);
+        assert_eq!(windows_reg(RU::rdi), 7);
+        assert_eq!(windows_reg(RU::rbp), 5);
+    }
+}